@@ -1,15 +1,50 @@
 #[cfg(feature = "with-serde")]
 use serde;
 
+use std::collections::HashMap;
 use std::default::Default;
 use std::fmt;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::mem;
+use std::ops::Deref;
 use std::option;
 
 use crate::clear::Clear;
 use crate::Message;
 
+/// A field's storage, abstracted over whether the field is singular,
+/// repeated, or a map.
+///
+/// This lets field-setting codegen, or any reflective mutation layer, write
+/// one code path ("does this container hold a value yet; if not, parse and
+/// insert one") across singular, repeated, and map fields instead of
+/// branching per-storage-kind.
+#[doc(hidden)]
+pub trait Container {
+    /// The type of value(s) held by this container: `T` for singular and
+    /// repeated fields, `(K, V)` for maps.
+    type Value;
+
+    /// True iff this container holds no values.
+    fn is_empty(&self) -> bool;
+
+    /// Remove all values. May retain backing storage for reuse.
+    ///
+    /// Named `clear_container` rather than `clear` because some
+    /// implementors (e.g. `Option<T>`) also implement [`Clear`] and have no
+    /// inherent `clear`, so a same-named trait method would make every
+    /// existing `.clear()` call on those types ambiguous.
+    fn clear_container(&mut self);
+
+    /// Reserve capacity for at least `additional` more values.
+    fn reserve(&mut self, additional: usize);
+
+    /// Add a value: overwrite for a singular field, append for a repeated
+    /// field, insert for a map.
+    fn push(&mut self, value: Self::Value);
+}
+
 /// Option-like objects
 #[doc(hidden)]
 pub trait OptionLike<T> {
@@ -22,6 +57,36 @@ pub trait OptionLike<T> {
         T: Default + Clear;
 }
 
+/// Implements `Container` for an `OptionLike<$value>` type, such a
+/// container holds at most one value, so `is_empty`/`push` reduce to the
+/// corresponding `OptionLike` operation and `reserve` is a no-op; only
+/// `clear_container` is written out, since whether it retains backing
+/// storage differs per type.
+///
+/// (A single generic `impl<T, O: OptionLike<T>> Container for O` can't be
+/// used here instead: it would conflict with the concrete `Vec`/`HashMap`
+/// impls below under Rust's coherence rules, since a downstream crate could
+/// in principle implement `OptionLike` for either of them.)
+macro_rules! option_like_container {
+    (<$($gen:ident),*> $ty:ty, Value = $value:ty, $clear_container:item) => {
+        impl<$($gen),*> Container for $ty {
+            type Value = $value;
+
+            fn is_empty(&self) -> bool {
+                self.as_option_ref().is_none()
+            }
+
+            $clear_container
+
+            fn reserve(&mut self, _additional: usize) {}
+
+            fn push(&mut self, value: $value) {
+                self.set_value(value);
+            }
+        }
+    };
+}
+
 impl<T> OptionLike<T> for Option<T> {
     fn into_option(self) -> Option<T> {
         self
@@ -54,6 +119,12 @@ impl<T> OptionLike<T> for Option<T> {
     }
 }
 
+option_like_container!(<T> Option<T>, Value = T,
+    fn clear_container(&mut self) {
+        *self = None;
+    }
+);
+
 impl<T> OptionLike<T> for Option<Box<T>> {
     fn into_option(self) -> Option<T> {
         self.map(|b| *b)
@@ -68,8 +139,10 @@ impl<T> OptionLike<T> for Option<Box<T>> {
     }
 
     fn set_value(&mut self, value: T) {
-        // TODO: reuse allocation
-        *self = Some(Box::new(value))
+        match self {
+            Some(b) => **b = value,
+            None => *self = Some(Box::new(value)),
+        }
     }
 
     fn set_default(&mut self) -> &mut T
@@ -385,8 +458,11 @@ impl<T> OptionLike<T> for SingularPtrField<T> {
     }
 
     fn set_value(&mut self, value: T) {
-        // TODO: unnecessary malloc
-        *self = SingularPtrField::some(value);
+        self.set = true;
+        match self.value {
+            Some(ref mut b) => **b = value,
+            None => self.value = Some(Box::new(value)),
+        }
     }
 
     /// Initialize this object with default value.
@@ -407,6 +483,12 @@ impl<T> OptionLike<T> for SingularPtrField<T> {
     }
 }
 
+option_like_container!(<T> SingularPtrField<T>, Value = T,
+    fn clear_container(&mut self) {
+        self.clear();
+    }
+);
+
 #[cfg(feature = "with-serde")]
 impl<T: serde::Serialize> serde::Serialize for SingularPtrField<T> {
     fn serialize<S>(
@@ -429,3 +511,615 @@ impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for SingularPtrFie
         Option::deserialize(deserializer).map(SingularPtrField::from)
     }
 }
+
+/// Like `Option<T>`, but keeps the actual element on `clear()`, so the
+/// storage backing `T` (e.g. a `String`'s or `Vec`'s heap buffer) can be
+/// reused instead of dropped and reallocated.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use protobuf::SingularField;
+/// # struct Customer {
+/// #     name: SingularField<String>,
+/// # }
+/// # impl Customer {
+/// #     fn new() -> Customer { unimplemented!() }
+/// # }
+/// #
+/// let mut customer = Customer::new();
+///
+/// // field of type `SingularField` can be initialized like this
+/// customer.name = SingularField::some("Ms Jones".to_string());
+/// // or using `Option` and `Into`
+/// customer.name = Some("Ms Jones".to_string()).into();
+/// ```
+pub struct SingularField<T> {
+    value: T,
+    set: bool,
+}
+
+impl<T> SingularField<T> {
+    /// Construct `SingularField` from given object.
+    #[inline]
+    pub fn some(value: T) -> SingularField<T> {
+        SingularField { value, set: true }
+    }
+
+    /// True iff this object contains data.
+    #[inline]
+    pub fn is_some(&self) -> bool {
+        self.set
+    }
+
+    /// True iff this object contains no data.
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        !self.is_some()
+    }
+
+    /// Convert into `Option<T>`.
+    #[inline]
+    pub fn into_option(self) -> Option<T> {
+        if self.set {
+            Some(self.value)
+        } else {
+            None
+        }
+    }
+
+    /// View data as reference option.
+    #[inline]
+    pub fn as_ref(&self) -> Option<&T> {
+        if self.set {
+            Some(&self.value)
+        } else {
+            None
+        }
+    }
+
+    /// View data as mutable reference option.
+    #[inline]
+    pub fn as_mut(&mut self) -> Option<&mut T> {
+        if self.set {
+            Some(&mut self.value)
+        } else {
+            None
+        }
+    }
+
+    /// Get data as reference.
+    /// Panics if empty.
+    #[inline]
+    pub fn get_ref(&self) -> &T {
+        self.as_ref().unwrap()
+    }
+
+    /// Get data as mutable reference.
+    /// Panics if empty.
+    #[inline]
+    pub fn get_mut_ref(&mut self) -> &mut T {
+        self.as_mut().unwrap()
+    }
+
+    /// Take the data.
+    /// Panics if empty
+    #[inline]
+    pub fn unwrap(self) -> T {
+        if self.set {
+            self.value
+        } else {
+            panic!();
+        }
+    }
+
+    /// Take the data or return supplied default element if empty.
+    #[inline]
+    pub fn unwrap_or(self, def: T) -> T {
+        if self.set {
+            self.value
+        } else {
+            def
+        }
+    }
+
+    /// Take the data or return supplied default element if empty.
+    #[inline]
+    pub fn unwrap_or_else<F>(self, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        if self.set {
+            self.value
+        } else {
+            f()
+        }
+    }
+
+    /// Apply given function to contained data to construct another `SingularField`.
+    /// Returns empty `SingularField` if this object is empty.
+    #[inline]
+    pub fn map<U, F>(self, f: F) -> SingularField<U>
+    where
+        F: FnOnce(T) -> U,
+        U: Default,
+    {
+        SingularField::from_option(self.into_option().map(f))
+    }
+
+    /// View data as iterator.
+    #[inline]
+    pub fn iter(&self) -> option::IntoIter<&T> {
+        self.as_ref().into_iter()
+    }
+
+    /// View data as mutable iterator.
+    #[inline]
+    pub fn mut_iter(&mut self) -> option::IntoIter<&mut T> {
+        self.as_mut().into_iter()
+    }
+
+    /// Clear this object, but do not drop or reset the underlying data.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.set = false;
+    }
+}
+
+impl<T: Default> SingularField<T> {
+    /// Construct an empty `SingularField`.
+    #[inline]
+    pub fn none() -> SingularField<T> {
+        SingularField {
+            value: T::default(),
+            set: false,
+        }
+    }
+
+    /// Construct `SingularField` from optional.
+    #[inline]
+    pub fn from_option(option: Option<T>) -> SingularField<T> {
+        match option {
+            Some(x) => SingularField::some(x),
+            None => SingularField::none(),
+        }
+    }
+
+    /// Take data as option, leaving this object empty.
+    #[inline]
+    pub fn take(&mut self) -> Option<T> {
+        if self.set {
+            self.set = false;
+            Some(mem::take(&mut self.value))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Default + Clear> SingularField<T> {
+    /// Get contained data, consume self. Return default value for type if this is empty.
+    #[inline]
+    pub fn unwrap_or_default(mut self) -> T {
+        if !self.set {
+            // Reuse the retained storage instead of dropping it and
+            // allocating a fresh `T::default()`.
+            self.value.clear();
+        }
+        self.value
+    }
+
+    /// Set object to `Some(T::default())`.
+    #[inline]
+    pub fn set_default(&mut self) -> &mut T {
+        OptionLike::set_default(self)
+    }
+}
+
+impl<M: Message + Default> SingularField<M> {
+    /// Get a reference to contained value or a default instance.
+    pub fn get_or_default(&self) -> &M {
+        self.as_ref().unwrap_or_else(|| M::default_instance())
+    }
+
+    /// Get a mutable reference to contained value, initialize if not initialized yet.
+    pub fn mut_or_default(&mut self) -> &mut M {
+        if self.is_none() {
+            self.set_default();
+        }
+        self.get_mut_ref()
+    }
+}
+
+impl<T: Default> Default for SingularField<T> {
+    #[inline]
+    fn default() -> SingularField<T> {
+        SingularField::none()
+    }
+}
+
+impl<T: Default> From<Option<T>> for SingularField<T> {
+    fn from(o: Option<T>) -> Self {
+        SingularField::from_option(o)
+    }
+}
+
+impl<T: Clone> Clone for SingularField<T> {
+    #[inline]
+    fn clone(&self) -> SingularField<T> {
+        SingularField {
+            value: self.value.clone(),
+            set: self.set,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SingularField<T> {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_some() {
+            write!(f, "Some({:?})", *self.as_ref().unwrap())
+        } else {
+            write!(f, "None")
+        }
+    }
+}
+
+impl<T: PartialEq> PartialEq for SingularField<T> {
+    #[inline]
+    fn eq(&self, other: &SingularField<T>) -> bool {
+        self.as_ref() == other.as_ref()
+    }
+}
+
+impl<T: Eq> Eq for SingularField<T> {}
+
+impl<T: Hash> Hash for SingularField<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_ref().hash(state);
+    }
+}
+
+impl<'a, T> IntoIterator for &'a SingularField<T> {
+    type Item = &'a T;
+    type IntoIter = option::IntoIter<&'a T>;
+
+    fn into_iter(self) -> option::IntoIter<&'a T> {
+        self.iter()
+    }
+}
+
+impl<T> OptionLike<T> for SingularField<T> {
+    fn into_option(self) -> Option<T> {
+        self.into_option()
+    }
+
+    fn as_option_ref(&self) -> Option<&T> {
+        self.as_ref()
+    }
+
+    fn as_option_mut(&mut self) -> Option<&mut T> {
+        self.as_mut()
+    }
+
+    fn set_value(&mut self, value: T) {
+        self.value = value;
+        self.set = true;
+    }
+
+    /// Initialize this object with default value.
+    /// This operation can be more efficient then construction of clear element,
+    /// because it reuses the previously contained object's storage.
+    #[inline]
+    fn set_default(&mut self) -> &mut T
+    where
+        T: Default + Clear,
+    {
+        self.set = true;
+        self.value.clear();
+        &mut self.value
+    }
+}
+
+option_like_container!(<T> SingularField<T>, Value = T,
+    fn clear_container(&mut self) {
+        self.clear();
+    }
+);
+
+#[cfg(feature = "with-serde")]
+impl<T: serde::Serialize> serde::Serialize for SingularField<T> {
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> Result<<S as serde::Serializer>::Ok, <S as serde::Serializer>::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.as_ref().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "with-serde")]
+impl<'de, T: serde::Deserialize<'de> + Default> serde::Deserialize<'de> for SingularField<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, <D as serde::Deserializer<'de>>::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Option::deserialize(deserializer).map(SingularField::from)
+    }
+}
+
+/// A thin wrapper around `Option<Box<T>>`, for use as a message field.
+///
+/// Unlike [`SingularPtrField`], `MessageField` derives from `Option<Box<T>>`
+/// and derefs to it, so the full `Option` API (`is_some`, `as_ref`, `map`,
+/// pattern matching, ...) is available directly, without learning a parallel
+/// set of methods.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use protobuf::MessageField;
+/// # struct Address {
+/// # }
+/// # struct Customer {
+/// #     address: MessageField<Address>,
+/// # }
+/// # impl Customer {
+/// #     fn new() -> Customer { unimplemented!() }
+/// # }
+/// #
+/// let mut customer = Customer::new();
+///
+/// // field of type `MessageField` can be initialized like this
+/// customer.address = MessageField::some(Address {});
+/// // or using `Option` and `Into`
+/// customer.address = Some(Address {}).into();
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct MessageField<T>(pub Option<Box<T>>);
+
+impl<T> MessageField<T> {
+    /// Construct `MessageField` from given object.
+    #[inline]
+    pub fn some(value: T) -> MessageField<T> {
+        MessageField(Some(Box::new(value)))
+    }
+
+    /// Construct an empty `MessageField`.
+    #[inline]
+    pub const fn none() -> MessageField<T> {
+        MessageField(None)
+    }
+
+    /// Construct `MessageField` from optional.
+    #[inline]
+    pub fn from_option(option: Option<T>) -> MessageField<T> {
+        MessageField(option.map(Box::new))
+    }
+}
+
+impl<M: Message + Default> MessageField<M> {
+    /// Get a reference to contained value or a default instance.
+    pub fn get_or_default(&self) -> &M {
+        self.0.as_deref().unwrap_or_else(|| M::default_instance())
+    }
+
+    /// Get a mutable reference to contained value, initialize if not initialized yet.
+    pub fn mut_or_default(&mut self) -> &mut M {
+        if self.0.is_none() {
+            self.0 = Some(Box::new(M::default()));
+        }
+        self.0.as_deref_mut().unwrap()
+    }
+}
+
+impl<T> Deref for MessageField<T> {
+    type Target = Option<Box<T>>;
+
+    #[inline]
+    fn deref(&self) -> &Option<Box<T>> {
+        &self.0
+    }
+}
+
+impl<T> Default for MessageField<T> {
+    #[inline]
+    fn default() -> MessageField<T> {
+        MessageField::none()
+    }
+}
+
+impl<T> From<Option<T>> for MessageField<T> {
+    fn from(o: Option<T>) -> Self {
+        MessageField::from_option(o)
+    }
+}
+
+impl<T> OptionLike<T> for MessageField<T> {
+    fn into_option(self) -> Option<T> {
+        self.0.into_option()
+    }
+
+    fn as_option_ref(&self) -> Option<&T> {
+        self.0.as_option_ref()
+    }
+
+    fn as_option_mut(&mut self) -> Option<&mut T> {
+        self.0.as_option_mut()
+    }
+
+    fn set_value(&mut self, value: T) {
+        self.0.set_value(value);
+    }
+
+    fn set_default(&mut self) -> &mut T
+    where
+        T: Default + Clear,
+    {
+        self.0.set_default()
+    }
+}
+
+option_like_container!(<T> MessageField<T>, Value = T,
+    fn clear_container(&mut self) {
+        self.0 = None;
+    }
+);
+
+impl<T> Container for Vec<T> {
+    type Value = T;
+
+    fn is_empty(&self) -> bool {
+        Vec::is_empty(self)
+    }
+
+    fn clear_container(&mut self) {
+        Vec::clear(self)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        Vec::reserve(self, additional)
+    }
+
+    fn push(&mut self, value: T) {
+        Vec::push(self, value)
+    }
+}
+
+impl<K: Eq + Hash, V> Container for HashMap<K, V> {
+    type Value = (K, V);
+
+    fn is_empty(&self) -> bool {
+        HashMap::is_empty(self)
+    }
+
+    fn clear_container(&mut self) {
+        HashMap::clear(self)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        HashMap::reserve(self, additional)
+    }
+
+    fn push(&mut self, (key, value): (K, V)) {
+        self.insert(key, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn option_box_set_value_reuses_allocation() {
+        let mut field: Option<Box<i32>> = None;
+        OptionLike::set_value(&mut field, 10);
+        let addr = field.as_ref().unwrap().as_ref() as *const i32;
+
+        // simulate a clear that keeps the box alive
+        if let Some(b) = field.as_mut() {
+            **b = 0;
+        }
+        OptionLike::set_value(&mut field, 20);
+
+        assert_eq!(**field.as_ref().unwrap(), 20);
+        assert_eq!(field.as_ref().unwrap().as_ref() as *const i32, addr);
+    }
+
+    #[test]
+    fn singular_ptr_field_set_value_reuses_allocation_across_clear() {
+        let mut field = SingularPtrField::some(10);
+        let addr = field.get_ref() as *const i32;
+
+        field.clear();
+        field.set_value(20);
+
+        assert_eq!(*field.get_ref(), 20);
+        assert_eq!(field.get_ref() as *const i32, addr);
+    }
+
+    #[test]
+    fn singular_field_set_default_reuses_storage_across_clear() {
+        let mut field: SingularField<String> = SingularField::none();
+        field.set_default().push_str("hello, this is a long enough string to allocate");
+        let cap = field.get_ref().capacity();
+
+        field.clear();
+        field.set_default();
+
+        assert_eq!(field.get_ref(), "");
+        assert_eq!(field.get_ref().capacity(), cap);
+    }
+
+    #[test]
+    fn singular_field_unwrap_or_default_reuses_storage_when_unset() {
+        let mut field: SingularField<String> = SingularField::none();
+        field.set_default().push_str("hello, this is a long enough string to allocate");
+        let ptr_before = field.get_ref().as_ptr();
+
+        field.clear();
+        let value = field.unwrap_or_default();
+
+        assert_eq!(value, "");
+        assert_eq!(value.as_ptr(), ptr_before);
+    }
+
+    #[test]
+    fn message_field_derefs_to_option_box() {
+        let mut field: MessageField<i32> = MessageField::none();
+        assert!(field.is_none());
+
+        field = MessageField::some(10);
+        assert_eq!(**field.as_ref().unwrap(), 10);
+
+        field.set_value(20);
+        assert_eq!(**field.as_ref().unwrap(), 20);
+
+        assert_eq!(field.into_option(), Some(20));
+    }
+
+    #[test]
+    fn clear_trait_not_ambiguous_with_container_on_option() {
+        let mut opt: Option<i32> = Some(10);
+        // Plain method-call syntax: `Clear` is already implemented for
+        // `Option<T>` and is in scope here. If `Container`'s
+        // `clear_container` were ever renamed back to `clear`, this call
+        // would become ambiguous (E0034) and fail to compile, catching the
+        // regression right here instead of at some unrelated call site.
+        opt.clear();
+    }
+
+    #[test]
+    fn container_vec_push_reserve_and_clear() {
+        let mut v: Vec<i32> = Vec::new();
+        assert!(Container::is_empty(&v));
+
+        Container::reserve(&mut v, 4);
+        Container::push(&mut v, 1);
+        Container::push(&mut v, 2);
+
+        assert!(!Container::is_empty(&v));
+        assert_eq!(v, vec![1, 2]);
+
+        Container::clear_container(&mut v);
+        assert!(Container::is_empty(&v));
+    }
+
+    #[test]
+    fn container_hash_map_push_and_clear() {
+        let mut m: HashMap<&str, i32> = HashMap::new();
+        assert!(Container::is_empty(&m));
+
+        Container::push(&mut m, ("a", 1));
+        Container::push(&mut m, ("b", 2));
+
+        assert!(!Container::is_empty(&m));
+        assert_eq!(m.get("a"), Some(&1));
+        assert_eq!(m.get("b"), Some(&2));
+
+        Container::clear_container(&mut m);
+        assert!(Container::is_empty(&m));
+    }
+}